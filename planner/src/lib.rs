@@ -0,0 +1,4 @@
+pub mod localizer;
+pub mod planner;
+pub mod points;
+pub mod spline;
@@ -0,0 +1,371 @@
+// belief-state tracking over DriveState so the planner can be seeded from a
+// noisy pose estimate instead of trusting raw odometry
+//
+// classic particle filter: predict with the motion model + process noise,
+// weight particles against the point map on each sensor frame, resample
+
+use rand::Rng;
+
+use crate::planner::DriveState;
+use crate::points::{Point, PointMap, Pos};
+
+// somewhere in the 1000-2000 range quoted in the design notes; enough
+// particles to cover the heading/speed uncertainty without costing too much
+// per update
+const NUM_PARTICLES: usize = 1500;
+
+// process noise added on every predict step
+const HEADING_NOISE_STD: f64 = 0.02; // rad
+const SPEED_NOISE_STD: f64 = 0.05; // m/s
+
+// sensor model: how precise we believe a single point observation is
+const RANGE_NOISE_STD: f64 = 0.1; // m
+const BEARING_NOISE_STD: f64 = 0.05; // rad
+
+// if the effective sample size drops below this fraction of NUM_PARTICLES,
+// or all weights collapse to ~0, treat the filter as depleted and reseed
+const MIN_EFFECTIVE_SAMPLE_FRACTION: f64 = 0.5;
+const MIN_TOTAL_WEIGHT: f64 = 1e-9;
+
+// spread of the reinjected particles' position around the measurement/
+// last-good estimate when recovering from depletion
+const REINJECT_POSITION_STD: f64 = 0.1; // m
+
+#[derive(Copy, Clone)]
+struct Particle {
+    pos: Pos,
+    angle: f64,
+    speed: f64,
+    weight: f64,
+}
+
+impl Particle {
+    fn state(&self) -> DriveState {
+        DriveState {
+            pos: self.pos,
+            angle: self.angle,
+            curvature: 0.0,
+            speed: self.speed,
+        }
+    }
+}
+
+// diagonal covariance over (x, y, angle), good enough for the planner to
+// widen clearance when the belief is spread out
+pub struct PoseCovariance {
+    pub var_x: f64,
+    pub var_y: f64,
+    pub var_angle: f64,
+}
+
+pub struct Localizer {
+    particles: Vec<Particle>,
+    // the last estimate produced while the filter was healthy, used to
+    // recover if it later depletes instead of reseeding around a pose that
+    // has already collapsed onto the wrong answer
+    last_good_estimate: DriveState,
+}
+
+impl Localizer {
+    pub fn new(initial: DriveState) -> Localizer {
+        let weight = 1.0 / NUM_PARTICLES as f64;
+        let particles = (0..NUM_PARTICLES)
+            .map(|_| Particle {
+                pos: initial.pos,
+                angle: initial.angle,
+                speed: initial.speed,
+                weight,
+            })
+            .collect();
+        Localizer {
+            particles,
+            last_good_estimate: initial,
+        }
+    }
+
+    // propagate every particle with the motion model, then jitter by process
+    // noise so the belief spreads to cover the true uncertainty
+    pub fn predict(&mut self, curvature: f64, speed: f64, dt: f64) {
+        let mut rng = rand::thread_rng();
+        for particle in &mut self.particles {
+            let next = DriveState {
+                pos: particle.pos,
+                angle: particle.angle,
+                curvature,
+                speed,
+            }
+            .step(dt);
+            particle.pos = next.pos;
+            particle.angle = next.angle + sample_gaussian(&mut rng, HEADING_NOISE_STD);
+            particle.speed = (next.speed + sample_gaussian(&mut rng, SPEED_NOISE_STD)).max(0.0);
+        }
+    }
+
+    // weight every particle by how well its pose explains the observed
+    // points, against the nearest same-type point in the map, then resample
+    pub fn update(&mut self, observed: &[Point], map: &impl PointMap) {
+        for particle in &mut self.particles {
+            let mut log_weight = 0.0;
+            for point in observed {
+                let Some(nearest) = nearest_of_same_type(particle, point, map) else {
+                    continue;
+                };
+                log_weight += observation_log_likelihood(particle, point, nearest);
+            }
+            // renormalize in log space before exponentiating to avoid
+            // underflow when many points are observed at once
+            particle.weight = log_weight;
+        }
+        let max_log_weight = self
+            .particles
+            .iter()
+            .fold(f64::NEG_INFINITY, |acc, p| acc.max(p.weight));
+        for particle in &mut self.particles {
+            particle.weight = (particle.weight - max_log_weight).exp();
+        }
+        // the shift above hides an absolute collapse, so check the log of
+        // the unshifted total against the threshold before normalizing
+        let shifted_total: f64 = self.particles.iter().map(|p| p.weight).sum();
+        let weights_collapsed = max_log_weight + shifted_total.ln() < MIN_TOTAL_WEIGHT.ln();
+        self.normalize_weights();
+
+        if weights_collapsed || self.is_depleted() {
+            self.reinject_around_estimate(observed, map);
+            self.normalize_weights();
+        } else {
+            self.last_good_estimate = self.estimate();
+        }
+
+        self.resample();
+    }
+
+    // weighted-mean pose, used directly as the planner's start_state
+    pub fn estimate(&self) -> DriveState {
+        let mut pos = Pos { x: 0.0, y: 0.0 };
+        let mut sin_sum = 0.0;
+        let mut cos_sum = 0.0;
+        let mut speed = 0.0;
+        for particle in &self.particles {
+            pos.x += particle.pos.x * particle.weight;
+            pos.y += particle.pos.y * particle.weight;
+            sin_sum += particle.angle.sin() * particle.weight;
+            cos_sum += particle.angle.cos() * particle.weight;
+            speed += particle.speed * particle.weight;
+        }
+        DriveState {
+            pos,
+            angle: sin_sum.atan2(cos_sum),
+            curvature: 0.0,
+            speed,
+        }
+    }
+
+    // spread of the belief, so the planner can pad clearance when unsure
+    pub fn covariance(&self) -> PoseCovariance {
+        let mean = self.estimate();
+        let mut var_x = 0.0;
+        let mut var_y = 0.0;
+        let mut var_angle = 0.0;
+        for particle in &self.particles {
+            let dx = particle.pos.x - mean.pos.x;
+            let dy = particle.pos.y - mean.pos.y;
+            let dangle = angle_diff(particle.angle, mean.angle);
+            var_x += dx * dx * particle.weight;
+            var_y += dy * dy * particle.weight;
+            var_angle += dangle * dangle * particle.weight;
+        }
+        PoseCovariance {
+            var_x,
+            var_y,
+            var_angle,
+        }
+    }
+
+    fn normalize_weights(&mut self) {
+        let total: f64 = self.particles.iter().map(|p| p.weight).sum();
+        if total < MIN_TOTAL_WEIGHT {
+            // every particle is an equally bad explanation; fall back to a
+            // uniform belief rather than dividing by ~0
+            let weight = 1.0 / self.particles.len() as f64;
+            for particle in &mut self.particles {
+                particle.weight = weight;
+            }
+            return;
+        }
+        for particle in &mut self.particles {
+            particle.weight /= total;
+        }
+    }
+
+    // effective sample size collapsing means a handful of particles are
+    // carrying all the belief: the filter has (or is about to) lose track
+    fn is_depleted(&self) -> bool {
+        let sum_sq: f64 = self.particles.iter().map(|p| p.weight * p.weight).sum();
+        if sum_sq <= 0.0 {
+            return true;
+        }
+        let effective_sample_size = 1.0 / sum_sq;
+        effective_sample_size < MIN_EFFECTIVE_SAMPLE_FRACTION * self.particles.len() as f64
+    }
+
+    // scatter a fresh batch of particles around the last good estimate, re-
+    // centered on the current measurement when one is available, so the
+    // filter can recover instead of converging on a wrong pose
+    fn reinject_around_estimate(&mut self, observed: &[Point], map: &impl PointMap) {
+        let fallback = self.last_good_estimate;
+        let measured_pos =
+            estimate_position_from_observations(fallback, observed, map).unwrap_or(fallback.pos);
+
+        let mut rng = rand::thread_rng();
+        let weight = 1.0 / self.particles.len() as f64;
+        for particle in &mut self.particles {
+            particle.pos = Pos {
+                x: measured_pos.x + sample_gaussian(&mut rng, REINJECT_POSITION_STD),
+                y: measured_pos.y + sample_gaussian(&mut rng, REINJECT_POSITION_STD),
+            };
+            particle.angle = fallback.angle + sample_gaussian(&mut rng, HEADING_NOISE_STD * 2.0);
+            particle.speed = fallback.speed.max(0.0);
+            particle.weight = weight;
+        }
+    }
+
+    // systematic resampling: a single random offset plus evenly spaced draws
+    // gives lower variance than naive multinomial resampling
+    fn resample(&mut self) {
+        let n = self.particles.len();
+        let mut cumulative = Vec::with_capacity(n);
+        let mut running = 0.0;
+        for particle in &self.particles {
+            running += particle.weight;
+            cumulative.push(running);
+        }
+
+        let mut rng = rand::thread_rng();
+        let start: f64 = rng.gen_range(0.0..1.0 / n as f64);
+        let weight = 1.0 / n as f64;
+
+        let mut resampled = Vec::with_capacity(n);
+        let mut cumulative_index = 0;
+        for i in 0..n {
+            let target = start + i as f64 / n as f64;
+            while cumulative_index < n - 1 && cumulative[cumulative_index] < target {
+                cumulative_index += 1;
+            }
+            let mut chosen = self.particles[cumulative_index];
+            chosen.weight = weight;
+            resampled.push(chosen);
+        }
+        self.particles = resampled;
+    }
+}
+
+// observations are given in the car's (sensor) frame, forward = +x; rotate
+// by the particle's heading before translating to get the world-frame
+// position the observation implies
+fn rotate(p: Pos, angle: f64) -> Pos {
+    Pos {
+        x: p.x * angle.cos() - p.y * angle.sin(),
+        y: p.x * angle.sin() + p.y * angle.cos(),
+    }
+}
+
+fn nearest_of_same_type<'a>(
+    particle: &Particle,
+    observed: &Point,
+    map: &'a impl PointMap,
+) -> Option<&'a Point> {
+    let rotated = rotate(observed.pos, particle.angle);
+    let world_pos = Pos {
+        x: particle.pos.x + rotated.x,
+        y: particle.pos.y + rotated.y,
+    };
+    map.get_points_in_area(world_pos, 1.0)
+        .into_iter()
+        .filter(|mapped| {
+            std::mem::discriminant(&mapped.point_type) == std::mem::discriminant(&observed.point_type)
+        })
+        .min_by(|a, b| {
+            a.pos
+                .dist(world_pos)
+                .partial_cmp(&b.pos.dist(world_pos))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+}
+
+// back out an implied car position from the observations by pairing each
+// one with its nearest same-type mapped point (searched around the last
+// good pose) and averaging mapped_pos - rotate(observed_pos, angle)
+fn estimate_position_from_observations(
+    probe_pose: DriveState,
+    observed: &[Point],
+    map: &impl PointMap,
+) -> Option<Pos> {
+    let probe = Particle {
+        pos: probe_pose.pos,
+        angle: probe_pose.angle,
+        speed: probe_pose.speed,
+        weight: 1.0,
+    };
+
+    let mut sum = Pos { x: 0.0, y: 0.0 };
+    let mut count = 0;
+    for point in observed {
+        let Some(mapped) = nearest_of_same_type(&probe, point, map) else {
+            continue;
+        };
+        let rotated = rotate(point.pos, probe_pose.angle);
+        sum.x += mapped.pos.x - rotated.x;
+        sum.y += mapped.pos.y - rotated.y;
+        count += 1;
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some(Pos {
+            x: sum.x / count as f64,
+            y: sum.y / count as f64,
+        })
+    }
+}
+
+// log of the Gaussian likelihood of the `observed` range/bearing, given the
+// particle's pose and the nearest mapped point it should correspond to,
+// scaled by the mapped point's confidence
+fn observation_log_likelihood(particle: &Particle, observed: &Point, mapped: &Point) -> f64 {
+    let observed_range = (observed.pos.x * observed.pos.x + observed.pos.y * observed.pos.y).sqrt();
+    let observed_bearing = observed.pos.y.atan2(observed.pos.x);
+
+    let dx = mapped.pos.x - particle.pos.x;
+    let dy = mapped.pos.y - particle.pos.y;
+    let expected_range = (dx * dx + dy * dy).sqrt();
+    let expected_bearing = angle_diff(dy.atan2(dx), particle.angle);
+
+    let range_error = observed_range - expected_range;
+    let bearing_error = angle_diff(observed_bearing, expected_bearing);
+
+    let range_term = -(range_error * range_error) / (2.0 * RANGE_NOISE_STD * RANGE_NOISE_STD);
+    let bearing_term =
+        -(bearing_error * bearing_error) / (2.0 * BEARING_NOISE_STD * BEARING_NOISE_STD);
+
+    (range_term + bearing_term) * mapped.confidence
+}
+
+fn angle_diff(a: f64, b: f64) -> f64 {
+    let mut diff = a - b;
+    while diff > std::f64::consts::PI {
+        diff -= 2.0 * std::f64::consts::PI;
+    }
+    while diff < -std::f64::consts::PI {
+        diff += 2.0 * std::f64::consts::PI;
+    }
+    diff
+}
+
+fn sample_gaussian(rng: &mut impl Rng, std_dev: f64) -> f64 {
+    // Box-Muller transform
+    let u1: f64 = rng.gen_range(1e-12..1.0);
+    let u2: f64 = rng.gen_range(0.0..1.0);
+    let z0 = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+    z0 * std_dev
+}
@@ -26,6 +26,7 @@
 // https://en.wikipedia.org/wiki/Motion_planning
 
 use std::cmp::{Ord, Reverse};
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::{cmp::Ordering, collections::BinaryHeap};
 
@@ -34,6 +35,7 @@ use opencv::highgui;
 use opencv::imgproc::circle;
 
 use crate::points::{Point, PointMap, PointType, Pos};
+use crate::spline::Spline;
 
 #[derive(Copy, Clone, PartialEq, Default)]
 pub struct DriveState {
@@ -56,92 +58,269 @@ impl DriveState {
                 speed: self.speed,
             };
         }
+        // advance along a circle of radius 1/curvature by angle
+        // curvature*dist: offset the center perpendicular to heading and
+        // rotate the position about it (exact unicycle arc integration)
         let radius = 1.0 / self.curvature;
+        let new_angle = self.angle + self.curvature * dist;
         return DriveState {
             pos: Pos {
-                x: 1.0,
-                y: dist.sin() * radius,
+                x: self.pos.x + radius * (new_angle.sin() - self.angle.sin()),
+                y: self.pos.y - radius * (new_angle.cos() - self.angle.cos()),
             },
-            angle: self.angle + self.curvature * dist,
+            angle: new_angle,
             curvature: self.curvature,
             speed: self.speed,
         };
     }
 
-    fn step(&self, time: f64) -> DriveState {
+    pub(crate) fn step(&self, time: f64) -> DriveState {
         let dist = time * self.speed;
         return self.step_distance(dist);
     }
 }
 
 mod distance_calculators {
-    use crate::points::Point;
+    use crate::points::{Point, PointType, Pos};
 
     use super::DriveState;
 
-    pub fn calculate_avoid_edge_weight_for_point(state: DriveState, point: &Point) -> f64 {
-        // add weight for being close to the point
-        let max_weight = 5.0;
-        let start_dist = 0.4;
-        let edge_dist = state.pos.dist(point.pos);
+    // reconstruct the left/right boundary as a polyline by chaining the
+    // nearby points of that type in the order the car would pass them,
+    // approximated by projecting them onto the car's forward axis
+    fn build_boundary_polyline(
+        state: DriveState,
+        nearby_points: &[&Point],
+        point_type: PointType,
+    ) -> Vec<Pos> {
+        let forward = (state.angle.cos(), state.angle.sin());
+        let mut points: Vec<Pos> = nearby_points
+            .iter()
+            .filter(|point| std::mem::discriminant(&point.point_type) == std::mem::discriminant(&point_type))
+            .map(|point| point.pos)
+            .collect();
+        points.sort_by(|a, b| {
+            let proj_a = (a.x - state.pos.x) * forward.0 + (a.y - state.pos.y) * forward.1;
+            let proj_b = (b.x - state.pos.x) * forward.0 + (b.y - state.pos.y) * forward.1;
+            proj_a.partial_cmp(&proj_b).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        points
+    }
+
+    // distance from p to the segment a->b, clamping the projection to stay
+    // on the segment rather than its infinite line
+    fn point_to_segment_distance(p: Pos, a: Pos, b: Pos) -> f64 {
+        let ab = (b.x - a.x, b.y - a.y);
+        let ab_len_sq = ab.0 * ab.0 + ab.1 * ab.1;
+        if ab_len_sq < 1e-9 {
+            return p.dist(a);
+        }
+        let ap = (p.x - a.x, p.y - a.y);
+        let h = ((ap.0 * ab.0 + ap.1 * ab.1) / ab_len_sq).clamp(0.0, 1.0);
+        let closest = Pos {
+            x: a.x + h * ab.0,
+            y: a.y + h * ab.1,
+        };
+        p.dist(closest)
+    }
+
+    // cross product sign of (b-a) x (p-a); positive means p is to the left
+    // of the segment when walking from a to b
+    fn side_of_segment(p: Pos, a: Pos, b: Pos) -> f64 {
+        (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+    }
+
+    // smooth signed-distance barrier against one boundary polyline: cost
+    // rises continuously as clearance shrinks, and crossing to the wrong
+    // side of the boundary (off the track) is penalized heavily
+    fn boundary_cost(pos: Pos, polyline: &[Pos], outside_is_left: bool, max_weight: f64) -> f64 {
+        let d_safe = 0.4;
+        if polyline.len() < 2 {
+            return 0.0;
+        }
+
+        let mut clearance = f64::MAX;
+        let mut crossed = false;
+        for segment in polyline.windows(2) {
+            let (a, b) = (segment[0], segment[1]);
+            let dist = point_to_segment_distance(pos, a, b);
+            if dist < clearance {
+                clearance = dist;
+                let side = side_of_segment(pos, a, b);
+                crossed = if outside_is_left { side > 0.0 } else { side < 0.0 };
+            }
+        }
 
-        // goes from max_weight when at the edge to 0 when at start_dist away from edge
-        let weighting = (start_dist - edge_dist) / start_dist * max_weight;
-        if weighting >= 0.0 {
-            weighting
+        let barrier = ((d_safe - clearance) / d_safe).max(0.0).powf(2.0) * max_weight;
+        if crossed {
+            barrier + max_weight
         } else {
-            0.0
+            barrier
         }
     }
 
+    pub fn calculate_avoid_edge_weight_for_boundaries(state: DriveState, nearby_points: &[&Point]) -> f64 {
+        let max_weight = 5.0;
+
+        let left_polyline = build_boundary_polyline(state, nearby_points, PointType::LeftLine);
+        let right_polyline = build_boundary_polyline(state, nearby_points, PointType::RightLine);
+
+        // the track is to the car's right of the left boundary and to its
+        // left of the right boundary, so "outside" flips between the two
+        boundary_cost(state.pos, &left_polyline, true, max_weight)
+            + boundary_cost(state.pos, &right_polyline, false, max_weight)
+    }
+
+    const DIRECTION_WEIGHT: f64 = 2.0;
+    const ARROW_WEIGHT_MULTIPLIER: f64 = 2.0;
+
     pub fn calculate_travel_direction_weight_for_point(state: DriveState, point: &Point) -> f64 {
         // add weight for travelling the wrong angular direction around points
         // extra for arrow points and none for obstacle points
-        0.0
+
+        // bearing to the point relative to heading, via the cross product
+        // of the forward vector and the vector to the point: positive means
+        // the point is to the car's left
+        let dx = point.pos.x - state.pos.x;
+        let dy = point.pos.y - state.pos.y;
+        let cross = state.angle.cos() * dy - state.angle.sin() * dx;
+        let is_on_left = cross > 0.0;
+
+        match point.point_type {
+            // left/right line cones mark the track edge: left cones must
+            // stay on the car's left, right (yellow) cones on its right
+            PointType::LeftLine => wrong_side_penalty(is_on_left, true, point.confidence, 1.0),
+            PointType::RightLine => wrong_side_penalty(is_on_left, false, point.confidence, 1.0),
+            // an arrow points the way to pass the marker, so it ends up on
+            // the opposite side to the direction it points; apply a
+            // stronger bias and also nudge curvature to bend that way
+            PointType::ArrowLeft => {
+                wrong_side_penalty(is_on_left, false, point.confidence, ARROW_WEIGHT_MULTIPLIER)
+                    + curvature_bias_penalty(state.curvature, true)
+            }
+            PointType::ArrowRight => {
+                wrong_side_penalty(is_on_left, true, point.confidence, ARROW_WEIGHT_MULTIPLIER)
+                    + curvature_bias_penalty(state.curvature, false)
+            }
+            PointType::Obstacle => 0.0,
+        }
+    }
+
+    fn wrong_side_penalty(
+        is_on_left: bool,
+        expected_left: bool,
+        confidence: f64,
+        weight_multiplier: f64,
+    ) -> f64 {
+        if is_on_left == expected_left {
+            0.0
+        } else {
+            DIRECTION_WEIGHT * weight_multiplier * confidence
+        }
+    }
+
+    // nudges the search toward curving the correct way around an arrow
+    // marker: arrow-left wants positive (leftward) curvature, arrow-right
+    // wants negative
+    fn curvature_bias_penalty(curvature: f64, wants_positive: bool) -> f64 {
+        let wrong_direction = if wants_positive {
+            curvature < 0.0
+        } else {
+            curvature > 0.0
+        };
+        if wrong_direction {
+            curvature.abs() * DIRECTION_WEIGHT
+        } else {
+            0.0
+        }
     }
 
     pub fn calculate_curvature_weight(state: DriveState) -> f64 {
         // add weighting to enourage taking smoother lines
         state.curvature.abs().powf(2.0) * 0.1
     }
+
+    pub fn calculate_progress_weight(state: DriveState) -> f64 {
+        // penalize travelling below an assumed top speed, instead of
+        // rewarding speed with a negative weight, so this term can never
+        // push the overall per-step cost below zero (the closed-set prune
+        // and g+h ordering in find_path both depend on non-negative costs)
+        let assumed_top_speed = 5.0;
+        let progress_weight = 0.05;
+        (assumed_top_speed - state.speed).max(0.0) * progress_weight
+    }
 }
 
-// calculates the distance/traversability map used for pathfinding
+// calculates the distance/traversability map used for pathfinding; always
+// >= 0 so accumulated g-cost is monotonically non-decreasing along any
+// path, which find_path's closed-set prune and heuristic ordering rely on
 fn distance(state: DriveState, nearby_points: &Vec<&Point>) -> f64 {
-    let mut total_weight = -0.1;
+    let mut total_weight = 0.0;
+    total_weight +=
+        distance_calculators::calculate_avoid_edge_weight_for_boundaries(state, nearby_points);
     for point in nearby_points {
-        total_weight += distance_calculators::calculate_avoid_edge_weight_for_point(state, point);
         total_weight +=
             distance_calculators::calculate_travel_direction_weight_for_point(state, point);
     }
     total_weight += distance_calculators::calculate_curvature_weight(state);
-    total_weight
+    total_weight += distance_calculators::calculate_progress_weight(state);
+    total_weight.max(0.0)
 }
 
 const MAX_CURVATURE: f64 = 1.0 / 0.3;
 
-fn get_possible_next_states(state: DriveState) -> Vec<DriveState> {
+const MAX_LINEAR_ACCEL: f64 = 2.0; // m/s^2
+const MAX_LINEAR_DECEL: f64 = 3.0; // m/s^2, braking is stronger than accelerating
+const MAX_LATERAL_ACCEL: f64 = 4.0; // m/s^2, before the tires would slip
+
+fn get_possible_next_states(state: DriveState, step_time: f64) -> Vec<DriveState> {
     let mut output = Vec::new();
     let turn_options = 3; // per side
+    let speed_deltas = [
+        -MAX_LINEAR_DECEL * step_time,
+        0.0,
+        MAX_LINEAR_ACCEL * step_time,
+    ];
     for new_turn_index in -turn_options..turn_options + 1 {
         let new_curvature = MAX_CURVATURE * (new_turn_index as f64 / turn_options as f64);
-        let new_drive_state = DriveState {
-            curvature: new_curvature,
-            ..state
-        };
-        output.push(new_drive_state.step(0.1));
+        for speed_delta in speed_deltas {
+            let new_speed = (state.speed + speed_delta).max(0.0);
+            // tight curvature forces a lower speed: reject any successor
+            // whose lateral acceleration would exceed the limit
+            let lateral_accel = new_speed * new_speed * new_curvature.abs();
+            if lateral_accel > MAX_LATERAL_ACCEL {
+                continue;
+            }
+            let new_drive_state = DriveState {
+                curvature: new_curvature,
+                speed: new_speed,
+                ..state
+            };
+            output.push(new_drive_state.step(step_time));
+        }
     }
     output
 }
 
 pub struct Path {
+    // the raw waypoints the search produced, one per expanded step
+    pub control_points: Vec<Pos>,
+    // the spline fit through control_points, flattened and resampled at a
+    // uniform arc-length spacing for downstream control to follow
     pub points: Vec<Pos>,
+    // curvature at each corresponding point in `points`, so a speed profile
+    // can use it without re-differentiating the path
+    pub curvatures: Vec<f64>,
+    // planned speed at each corresponding point in `points`, for a
+    // longitudinal controller to follow
+    pub speeds: Vec<f64>,
 }
 
 #[derive(Clone)]
 struct PathNodeData {
     pub state: DriveState,
-    pub distance: f64,
+    pub distance: f64, // accumulated g-cost
+    pub priority: f64, // g-cost + weighted heuristic; what the open set orders on
     pub prev: Rc<PathNode>,
     pub steps: u32,
 }
@@ -153,15 +332,15 @@ enum PathNode {
 
 impl PartialEq for PathNodeData {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.priority == other.priority
     }
 }
 impl Eq for PathNodeData {}
 impl PartialOrd for PathNodeData {
     fn partial_cmp(&self, other: &PathNodeData) -> Option<Ordering> {
         let regular_ordering = self
-            .distance
-            .partial_cmp(&other.distance)
+            .priority
+            .partial_cmp(&other.priority)
             .expect("should not have NaN distances");
         // order is reversed so that std::BinaryHeap, which is usually a max heap, acts as a min heap
         Some(Reverse(regular_ordering).0)
@@ -186,45 +365,119 @@ const PLAN_STEP_SIZE_SECONDS: f64 = 0.1;
 const PLAN_LENGTH_SECONDS: f64 = 3.0;
 const PLAN_STEPS: u32 = (PLAN_LENGTH_SECONDS / PLAN_STEP_SIZE_SECONDS) as u32;
 
+// used only to scale the goal heuristic into the same units as the
+// accumulated per-step cost
+const ASSUMED_MAX_SPEED: f64 = 5.0;
+// being this close to the goal counts as having arrived
+const GOAL_RADIUS: f64 = 0.3;
+
+// straight-line travel time to the goal at ASSUMED_MAX_SPEED, scaled by
+// dist_to_goal_weight; larger weights make the search more goal-directed.
+// This is not a lower bound on the remaining accumulated cost, whatever the
+// weight: a straight step at or above ASSUMED_MAX_SPEED with no nearby points
+// costs 0 in `distance()`, and successor speeds are not capped at
+// ASSUMED_MAX_SPEED, so the returned path is not guaranteed to be the
+// cheapest one.
+fn heuristic(pos: Pos, goal: Pos, dist_to_goal_weight: f64) -> f64 {
+    pos.dist(goal) / ASSUMED_MAX_SPEED * dist_to_goal_weight
+}
+
+// cell size for the closed-set dedup; coarser than the point-map grid since
+// this just needs to catch near-duplicate search states, not track position
+// precisely. Sound because `distance()` is non-negative, so a cell's best
+// known g-cost can only be beaten by a cheaper path, never by revisiting.
+const CLOSED_SET_POS_CELL: f64 = 0.1;
+const CLOSED_SET_ANGLE_CELL: f64 = 0.1;
+const CLOSED_SET_CURVATURE_CELL: f64 = 0.05;
+
+fn closed_set_cell(state: DriveState) -> (i64, i64, i64, i64) {
+    (
+        (state.pos.x / CLOSED_SET_POS_CELL).floor() as i64,
+        (state.pos.y / CLOSED_SET_POS_CELL).floor() as i64,
+        (state.angle / CLOSED_SET_ANGLE_CELL).floor() as i64,
+        (state.curvature / CLOSED_SET_CURVATURE_CELL).floor() as i64,
+    )
+}
+
 impl Planner {
     pub fn new() -> Planner {
         Planner {}
     }
 
-    pub fn find_path(&self, start_state: DriveState, points: &impl PointMap) -> Path {
+    pub fn find_path(
+        &self,
+        start_state: DriveState,
+        points: &impl PointMap,
+        goal: Option<Pos>,
+        dist_to_goal_weight: f64,
+    ) -> Path {
         puffin::profile_function!();
 
+        let start_priority =
+            goal.map_or(0.0, |goal| heuristic(start_state.pos, goal, dist_to_goal_weight));
+
         // https://doc.rust-lang.org/std/collections/binary_heap/index.html
         let mut open_set = BinaryHeap::new();
         open_set.push(PathNodeData {
             state: start_state,
             distance: 0.0,
+            priority: start_priority,
             prev: Rc::new(PathNode::End),
             steps: 0,
         });
+
+        // best g-cost seen so far for each discretized (x, y, angle,
+        // curvature) cell, so the frontier doesn't keep expanding states
+        // that are effectively duplicates of ones already settled
+        let mut best_cost_for_cell: HashMap<(i64, i64, i64, i64), f64> = HashMap::new();
+
         while let Some(current) = open_set.pop() {
+            let cell = closed_set_cell(current.state);
+            if let Some(&best) = best_cost_for_cell.get(&cell) {
+                if best <= current.distance {
+                    continue;
+                }
+            }
+            best_cost_for_cell.insert(cell, current.distance);
+
             let current_rc = Rc::new(PathNode::Node(current.clone()));
 
-            if current.steps > PLAN_STEPS {
+            let reached_goal =
+                goal.map_or(false, |goal| current.state.pos.dist(goal) < GOAL_RADIUS);
+            if reached_goal || current.steps > PLAN_STEPS {
                 let final_path = reconstruct_path(current);
                 draw_map_debug(&points.get_points_in_area(Pos{x:0., y:0.}, 999.0), &final_path).unwrap();
                 return final_path;
             }
 
-            let next_drive_states = get_possible_next_states(current.state).into_iter();
+            let next_drive_states =
+                get_possible_next_states(current.state, PLAN_STEP_SIZE_SECONDS).into_iter();
             let relevant_points = points.get_points_in_area(current.state.pos, 0.5); // TODO: magic number
-            let get_node_from_state = |state| {
+            let get_node_from_state = |state: DriveState| {
+                let distance = current.distance + distance(state, &relevant_points);
+                let priority = distance
+                    + goal.map_or(0.0, |goal| heuristic(state.pos, goal, dist_to_goal_weight));
                 PathNodeData {
-                    state: state,
-                    distance: current.distance + distance(state, &relevant_points),
+                    state,
+                    distance,
+                    priority,
                     prev: current_rc.clone(),
                     steps: current.steps + 1,
                 }
             };
-            let next_nodes = next_drive_states.map(get_node_from_state);
+            let next_nodes = next_drive_states.map(get_node_from_state).filter(|node| {
+                best_cost_for_cell
+                    .get(&closed_set_cell(node.state))
+                    .map_or(true, |&best| node.distance < best)
+            });
             open_set.extend(next_nodes);
         }
-        let no_path = Path { points: Vec::new() };
+        let no_path = Path {
+            control_points: Vec::new(),
+            points: Vec::new(),
+            curvatures: Vec::new(),
+            speeds: Vec::new(),
+        };
         draw_map_debug(&points.get_points_in_area(Pos{x:0., y:0.}, 999.0), &no_path).unwrap();
         no_path
     }
@@ -234,13 +487,16 @@ fn reconstruct_path(final_node: PathNodeData) -> Path {
     puffin::profile_function!();
 
     let mut path = Vec::new();
+    let mut speeds = Vec::new();
     path.push(final_node.state.pos);
+    speeds.push(final_node.state.speed);
     let mut current = final_node.prev;
     loop {
         match current.as_ref() {
             PathNode::End => break,
             PathNode::Node(node_data) => {
                 path.push(node_data.state.pos);
+                speeds.push(node_data.state.speed);
                 println!("{:?}", node_data.state.pos);
                 current = node_data.prev.clone();
             }
@@ -248,7 +504,19 @@ fn reconstruct_path(final_node: PathNodeData) -> Path {
     }
     println!("{}", path.len());
     path.reverse();
-    Path { points: path }
+    speeds.reverse();
+
+    let curve = Spline::fit(&path);
+    let flattened = curve.flatten();
+    Path {
+        control_points: path,
+        points: flattened.iter().map(|sample| sample.pos).collect(),
+        curvatures: flattened.iter().map(|sample| sample.curvature).collect(),
+        speeds: flattened
+            .iter()
+            .map(|sample| curve.interpolate(sample, &speeds))
+            .collect(),
+    }
 }
 
 
@@ -284,3 +552,38 @@ pub fn draw_map_debug(point_map: &Vec<&Point>, path: &Path) -> Result<(), opencv
     highgui::imshow("map", &display)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    #[test]
+    fn quarter_circle_arc_integration() {
+        // curvature 1.0 -> radius 1.0; a quarter-circle turn left from the
+        // origin heading +x should end up at (1, 1) heading +y
+        let state = DriveState {
+            pos: Pos { x: 0.0, y: 0.0 },
+            angle: 0.0,
+            curvature: 1.0,
+            speed: 1.0,
+        };
+        let next = state.step_distance(FRAC_PI_2);
+        assert!((next.pos.x - 1.0).abs() < 1e-9);
+        assert!((next.pos.y - 1.0).abs() < 1e-9);
+        assert!((next.angle - FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn straight_line_step_distance() {
+        let state = DriveState {
+            pos: Pos { x: 0.0, y: 0.0 },
+            angle: 0.0,
+            curvature: 0.0,
+            speed: 2.0,
+        };
+        let next = state.step_distance(3.0);
+        assert!((next.pos.x - 3.0).abs() < 1e-9);
+        assert!(next.pos.y.abs() < 1e-9);
+    }
+}
@@ -45,6 +45,9 @@ pub trait PointMap {
     fn get_points_below_confidence(&self, cutoff: f64) -> Vec<&Point>;
     fn get_points_lowest_confidence(&self, number: f64) -> Vec<&Point>;
     fn add_points(&mut self, points: &mut Vec<Point>);
+    // drops stale points so map-maintenance can reclaim space; returns the
+    // number removed
+    fn remove_points_below_confidence(&mut self, cutoff: f64) -> usize;
 }
 
 pub struct SimplePointMap {
@@ -72,26 +75,119 @@ impl PointMap for SimplePointMap {
     }
 
     fn get_points_below_confidence(&self, cutoff: f64) -> Vec<&Point> {
-        vec![]
+        self.all_points
+            .iter()
+            .filter(|point| point.confidence < cutoff)
+            .collect()
     }
 
     fn get_points_lowest_confidence(&self, number: f64) -> Vec<&Point> {
-        vec![]
+        let number = number as usize;
+        let mut points: Vec<&Point> = self.all_points.iter().collect();
+        points.sort_by(|a, b| {
+            a.confidence
+                .partial_cmp(&b.confidence)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        points.truncate(number);
+        points
+    }
+
+    fn remove_points_below_confidence(&mut self, cutoff: f64) -> usize {
+        let before = self.all_points.len();
+        self.all_points.retain(|point| point.confidence >= cutoff);
+        before - self.all_points.len()
     }
 }
 
 const GRID_SIZE: f64 = 0.2;
 
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 struct GridIndex {
     x: i16,
     y: i16,
 }
+
+fn grid_index_for(pos: Pos) -> GridIndex {
+    GridIndex {
+        x: (pos.x / GRID_SIZE).floor() as i16,
+        y: (pos.y / GRID_SIZE).floor() as i16,
+    }
+}
+
 pub struct GridPointMap {
     grid: HashMap<GridIndex, Vec<Point>>,
 }
 
-// impl PointMap for GridPointMap {
-//     fn get_points(&self, around: Pos, max_dist: f64) -> Vec<Point>{
+impl GridPointMap {
+    pub fn new() -> GridPointMap {
+        GridPointMap {
+            grid: HashMap::new(),
+        }
+    }
+}
 
-//     }
-// }
+impl PointMap for GridPointMap {
+    fn get_points_in_area(&self, around: Pos, max_dist: f64) -> Vec<&Point> {
+        let center = grid_index_for(around);
+        // only the cells that could possibly contain a point within
+        // max_dist need to be visited, instead of a full linear scan
+        let cell_radius = (max_dist / GRID_SIZE).ceil() as i16;
+
+        let mut points = Vec::new();
+        for dx in -cell_radius..=cell_radius {
+            for dy in -cell_radius..=cell_radius {
+                let index = GridIndex {
+                    x: center.x + dx,
+                    y: center.y + dy,
+                };
+                let Some(cell) = self.grid.get(&index) else {
+                    continue;
+                };
+                points.extend(
+                    cell.iter()
+                        .filter(|point| point.pos.dist(around) < max_dist),
+                );
+            }
+        }
+        points
+    }
+
+    fn get_points_below_confidence(&self, cutoff: f64) -> Vec<&Point> {
+        self.grid
+            .values()
+            .flatten()
+            .filter(|point| point.confidence < cutoff)
+            .collect()
+    }
+
+    fn get_points_lowest_confidence(&self, number: f64) -> Vec<&Point> {
+        let number = number as usize;
+        let mut points: Vec<&Point> = self.grid.values().flatten().collect();
+        points.sort_by(|a, b| {
+            a.confidence
+                .partial_cmp(&b.confidence)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        points.truncate(number);
+        points
+    }
+
+    fn add_points(&mut self, points: &mut Vec<Point>) {
+        for point in points.drain(..) {
+            let index = grid_index_for(point.pos);
+            self.grid.entry(index).or_insert_with(Vec::new).push(point);
+        }
+    }
+
+    fn remove_points_below_confidence(&mut self, cutoff: f64) -> usize {
+        let mut removed = 0;
+        self.grid.retain(|_, cell| {
+            let before = cell.len();
+            cell.retain(|point| point.confidence >= cutoff);
+            removed += before - cell.len();
+            !cell.is_empty()
+        });
+        removed
+    }
+}
@@ -0,0 +1,318 @@
+// fits a smooth curve through the planner's waypoints so downstream control
+// gets a continuous reference line instead of the raw per-step samples
+//
+// Catmull-Rom through the waypoints, flattened adaptively (tolerance-driven,
+// like curve-approximation libraries do) and then resampled at a fixed
+// arc-length spacing
+
+use crate::points::Pos;
+
+// target spacing between points in the final, resampled path
+pub const ARC_LENGTH_SPACING: f64 = 0.1;
+// a flattened segment is subdivided further if its midpoint strays more
+// than this from the midpoint of the straight chord between its endpoints
+// (which also bounds its distance from the chord itself)
+const FLATNESS_TOLERANCE: f64 = 0.01;
+const MAX_SUBDIVISION_DEPTH: u32 = 16;
+
+#[derive(Copy, Clone)]
+pub struct CurveSample {
+    pub pos: Pos,
+    pub curvature: f64,
+    // which control-point segment and parameter this sample came from, so
+    // other per-control-point values (e.g. speed) can be interpolated to
+    // match
+    segment: usize,
+    t: f64,
+}
+
+pub struct Spline {
+    control_points: Vec<Pos>,
+}
+
+impl Spline {
+    pub fn fit(waypoints: &[Pos]) -> Spline {
+        Spline {
+            control_points: waypoints.to_vec(),
+        }
+    }
+
+    pub fn control_points(&self) -> &[Pos] {
+        &self.control_points
+    }
+
+    // linearly interpolate a per-control-point scalar (e.g. speed) to the
+    // location of a flattened sample
+    pub fn interpolate(&self, sample: &CurveSample, values: &[f64]) -> f64 {
+        if values.is_empty() {
+            return 0.0;
+        }
+        let a = values[sample.segment.min(values.len() - 1)];
+        let b = values[(sample.segment + 1).min(values.len() - 1)];
+        a + (b - a) * sample.t
+    }
+
+    // position at parameter t (0..1) along segment i -> i+1, clamping the
+    // neighbor lookups at the ends so the first/last segment still has
+    // well-defined tangents
+    fn position(&self, segment: usize, t: f64) -> Pos {
+        let (p0, m1, p2, m2) = self.hermite_terms(segment);
+        hermite(p0, m1, p2, m2, t)
+    }
+
+    fn curvature(&self, segment: usize, t: f64) -> f64 {
+        let (p1, m1, p2, m2) = self.hermite_terms(segment);
+        let (vx, vy) = hermite_first_derivative(p1, m1, p2, m2, t);
+        let (ax, ay) = hermite_second_derivative(p1, m1, p2, m2, t);
+        let speed_sq = vx * vx + vy * vy;
+        if speed_sq < 1e-9 {
+            return 0.0;
+        }
+        (vx * ay - vy * ax) / speed_sq.powf(1.5)
+    }
+
+    fn hermite_terms(&self, segment: usize) -> (Pos, Pos, Pos, Pos) {
+        let n = self.control_points.len();
+        let p0 = self.control_points[segment.saturating_sub(1)];
+        let p1 = self.control_points[segment];
+        let p2 = self.control_points[(segment + 1).min(n - 1)];
+        let p3 = self.control_points[(segment + 2).min(n - 1)];
+
+        let m1 = Pos {
+            x: (p2.x - p0.x) / 2.0,
+            y: (p2.y - p0.y) / 2.0,
+        };
+        let m2 = Pos {
+            x: (p3.x - p1.x) / 2.0,
+            y: (p3.y - p1.y) / 2.0,
+        };
+        (p1, m1, p2, m2)
+    }
+
+    // adaptively flatten every segment, then resample the dense polyline at
+    // a uniform arc-length spacing
+    pub fn flatten(&self) -> Vec<CurveSample> {
+        if self.control_points.len() < 2 {
+            return self
+                .control_points
+                .iter()
+                .enumerate()
+                .map(|(i, &pos)| CurveSample {
+                    pos,
+                    curvature: 0.0,
+                    segment: i,
+                    t: 0.0,
+                })
+                .collect();
+        }
+
+        let mut dense = Vec::new();
+        for segment in 0..self.control_points.len() - 1 {
+            self.flatten_segment(segment, 0.0, 1.0, 0, &mut dense);
+        }
+        let last_segment = self.control_points.len() - 2;
+        dense.push((last_segment, 1.0, self.position(last_segment, 1.0)));
+
+        resample_by_arc_length(self, &dense)
+    }
+
+    fn flatten_segment(
+        &self,
+        segment: usize,
+        t0: f64,
+        t1: f64,
+        depth: u32,
+        out: &mut Vec<(usize, f64, Pos)>,
+    ) {
+        let p0 = self.position(segment, t0);
+        let p1 = self.position(segment, t1);
+        let mid_t = (t0 + t1) / 2.0;
+        let mid_curve = self.position(segment, mid_t);
+        let mid_chord = p0.lerp(p1, 0.5);
+
+        let flat_enough =
+            depth >= MAX_SUBDIVISION_DEPTH || mid_curve.dist(mid_chord) < FLATNESS_TOLERANCE;
+        if flat_enough {
+            out.push((segment, t0, p0));
+        } else {
+            self.flatten_segment(segment, t0, mid_t, depth + 1, out);
+            self.flatten_segment(segment, mid_t, t1, depth + 1, out);
+        }
+    }
+}
+
+fn resample_by_arc_length(spline: &Spline, dense: &[(usize, f64, Pos)]) -> Vec<CurveSample> {
+    let mut samples = Vec::new();
+    let (segment, t, pos) = dense[0];
+    samples.push(CurveSample {
+        pos,
+        curvature: spline.curvature(segment, t),
+        segment,
+        t,
+    });
+
+    let mut accumulated = 0.0;
+    let mut next_target = ARC_LENGTH_SPACING;
+    for window in dense.windows(2) {
+        let (seg_a, t_a, pos_a) = window[0];
+        let (seg_b, t_b, pos_b) = window[1];
+        let segment_len = pos_a.dist(pos_b);
+        if segment_len < 1e-12 {
+            continue;
+        }
+
+        while accumulated + segment_len >= next_target {
+            let remaining = next_target - accumulated;
+            let frac = remaining / segment_len;
+            let pos = pos_a.lerp(pos_b, frac);
+            // dense points are close together, so linearly blending t
+            // along an edge is a reasonable local approximation for
+            // curvature at the interpolated point; an edge that ends at
+            // the start (t = 0) of the next segment ends at t = 1 of its own
+            let t_end = if seg_b == seg_a { t_b } else { 1.0 };
+            let segment = seg_a;
+            let t = t_a + (t_end - t_a) * frac;
+            let t = t.clamp(0.0, 1.0);
+            samples.push(CurveSample {
+                pos,
+                curvature: spline.curvature(segment, t),
+                segment,
+                t,
+            });
+            next_target += ARC_LENGTH_SPACING;
+        }
+        accumulated += segment_len;
+    }
+
+    let (last_segment, last_t, last_pos) = *dense.last().unwrap();
+    if samples.last().map(|s| s.pos) != Some(last_pos) {
+        samples.push(CurveSample {
+            pos: last_pos,
+            curvature: spline.curvature(last_segment, last_t),
+            segment: last_segment,
+            t: last_t,
+        });
+    }
+    samples
+}
+
+fn hermite(p1: Pos, m1: Pos, p2: Pos, m2: Pos, t: f64) -> Pos {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+    let h10 = t3 - 2.0 * t2 + t;
+    let h01 = -2.0 * t3 + 3.0 * t2;
+    let h11 = t3 - t2;
+    Pos {
+        x: h00 * p1.x + h10 * m1.x + h01 * p2.x + h11 * m2.x,
+        y: h00 * p1.y + h10 * m1.y + h01 * p2.y + h11 * m2.y,
+    }
+}
+
+fn hermite_first_derivative(p1: Pos, m1: Pos, p2: Pos, m2: Pos, t: f64) -> (f64, f64) {
+    let t2 = t * t;
+    let h00 = 6.0 * t2 - 6.0 * t;
+    let h10 = 3.0 * t2 - 4.0 * t + 1.0;
+    let h01 = -6.0 * t2 + 6.0 * t;
+    let h11 = 3.0 * t2 - 2.0 * t;
+    (
+        h00 * p1.x + h10 * m1.x + h01 * p2.x + h11 * m2.x,
+        h00 * p1.y + h10 * m1.y + h01 * p2.y + h11 * m2.y,
+    )
+}
+
+fn hermite_second_derivative(p1: Pos, m1: Pos, p2: Pos, m2: Pos, t: f64) -> (f64, f64) {
+    let h00 = 12.0 * t - 6.0;
+    let h10 = 6.0 * t - 4.0;
+    let h01 = -12.0 * t + 6.0;
+    let h11 = 6.0 * t - 2.0;
+    (
+        h00 * p1.x + h10 * m1.x + h01 * p2.x + h11 * m2.x,
+        h00 * p1.y + h10 * m1.y + h01 * p2.y + h11 * m2.y,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn arc_points(radius: f64, center: Pos, angle_start: f64, angle_end: f64, n: usize) -> Vec<Pos> {
+        (0..=n)
+            .map(|i| {
+                let t = angle_start + (angle_end - angle_start) * (i as f64 / n as f64);
+                Pos {
+                    x: center.x + radius * t.cos(),
+                    y: center.y + radius * t.sin(),
+                }
+            })
+            .collect()
+    }
+
+    fn distance_from_chord(p: Pos, a: Pos, b: Pos) -> f64 {
+        let ab = (b.x - a.x, b.y - a.y);
+        let len_sq = ab.0 * ab.0 + ab.1 * ab.1;
+        if len_sq < 1e-12 {
+            return p.dist(a);
+        }
+        let ap = (p.x - a.x, p.y - a.y);
+        let h = (ap.0 * ab.0 + ap.1 * ab.1) / len_sq;
+        let closest = Pos {
+            x: a.x + h * ab.0,
+            y: a.y + h * ab.1,
+        };
+        p.dist(closest)
+    }
+
+    fn max_deviation_from_chord(samples: &[CurveSample]) -> f64 {
+        let first = samples.first().unwrap().pos;
+        let last = samples.last().unwrap().pos;
+        samples
+            .iter()
+            .map(|s| distance_from_chord(s.pos, first, last))
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn straight_line_has_zero_curvature() {
+        let points = vec![
+            Pos { x: 0.0, y: 0.0 },
+            Pos { x: 1.0, y: 0.0 },
+            Pos { x: 2.0, y: 0.0 },
+            Pos { x: 3.0, y: 0.0 },
+        ];
+        let samples = Spline::fit(&points).flatten();
+        for sample in &samples {
+            assert!(sample.curvature.abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn known_arc_has_expected_curvature() {
+        let radius = 2.0;
+        let points = arc_points(radius, Pos { x: 0.0, y: 0.0 }, 0.0, FRAC_PI_2, 6);
+        let spline = Spline::fit(&points);
+        // sample an interior segment, away from the end-padding that skews
+        // the first/last segment's tangents
+        let segment = points.len() / 2;
+        let curvature = spline.curvature(segment, 0.5);
+        assert!((curvature.abs() - 1.0 / radius).abs() < 0.2);
+    }
+
+    #[test]
+    fn resample_follows_curve_not_chord() {
+        let straight = vec![
+            Pos { x: 0.0, y: 0.0 },
+            Pos { x: 1.0, y: 0.0 },
+            Pos { x: 2.0, y: 0.0 },
+        ];
+        // quarter circle from (0, 0) to (1, 1) around center (0, 1)
+        let curved = arc_points(1.0, Pos { x: 0.0, y: 1.0 }, -FRAC_PI_2, 0.0, 4);
+
+        let straight_samples = Spline::fit(&straight).flatten();
+        let curved_samples = Spline::fit(&curved).flatten();
+
+        assert!(max_deviation_from_chord(&straight_samples) < 1e-6);
+        assert!(max_deviation_from_chord(&curved_samples) > 0.05);
+    }
+}